@@ -1,14 +1,14 @@
 use anyhow::{bail, Context, Result};
 use next_core::{
     middleware::get_middleware_module,
-    mode::NextMode,
     next_edge::entry::wrap_edge_entry,
     next_manifests::{EdgeFunctionDefinition, MiddlewareMatcher, MiddlewaresManifestV2},
     next_server::{get_server_runtime_entries, ServerContextType},
     util::parse_config_from_source,
 };
+use serde::{Deserialize, Serialize};
 use tracing::Instrument;
-use turbo_tasks::{Completion, TryJoinIterExt, Value, Vc};
+use turbo_tasks::{Completion, RcStr, TryJoinIterExt, Value, Vc};
 use turbopack_binding::{
     turbo::tasks_fs::{File, FileContent},
     turbopack::{
@@ -70,7 +70,7 @@ impl MiddlewareEndpoint {
 
         let mut evaluatable_assets = get_server_runtime_entries(
             Value::new(ServerContextType::Middleware),
-            NextMode::Development,
+            *self.project.next_mode().await?,
         )
         .resolve_entries(self.context)
         .await?
@@ -95,13 +95,71 @@ impl MiddlewareEndpoint {
         Ok(edge_files)
     }
 
+    /// Builds the middleware entry for `export const config = { runtime:
+    /// 'nodejs' }`. Unlike [`Self::edge_files`], the middleware module is used
+    /// as the entry without `wrap_edge_entry`: there's no edge sandbox to wrap
+    /// it for.
+    #[turbo_tasks::function]
+    async fn node_files(&self) -> Result<Vc<OutputAssets>> {
+        let module = get_middleware_module(
+            self.context,
+            self.project.project_path(),
+            self.userland_module,
+        );
+
+        let mut evaluatable_assets = get_server_runtime_entries(
+            Value::new(ServerContextType::Middleware),
+            *self.project.next_mode().await?,
+        )
+        .resolve_entries(self.context)
+        .await?
+        .clone_value();
+
+        let Some(module) =
+            Vc::try_resolve_downcast::<Box<dyn EcmascriptChunkPlaceable>>(module).await?
+        else {
+            bail!("Entry module must be evaluatable");
+        };
+
+        let Some(evaluatable) = Vc::try_resolve_sidecast(module).await? else {
+            bail!("Entry module must be evaluatable");
+        };
+        evaluatable_assets.push(evaluatable);
+
+        let node_chunking_context = self.project.server_chunking_context();
+
+        let node_files = node_chunking_context
+            .evaluated_chunk_group(module.ident(), Vc::cell(evaluatable_assets));
+
+        Ok(node_files)
+    }
+
+    /// The runtime the userland module's `config` exports declared for this
+    /// middleware, defaulting to `"edge"` when unset.
+    #[turbo_tasks::function]
+    async fn runtime(&self) -> Result<Vc<RcStr>> {
+        let config = parse_config_from_source(self.userland_module);
+        let runtime = config
+            .await?
+            .runtime
+            .as_deref()
+            .unwrap_or("edge")
+            .to_string();
+        Ok(Vc::cell(runtime.into()))
+    }
+
     #[turbo_tasks::function]
     async fn output_assets(self: Vc<Self>) -> Result<Vc<OutputAssets>> {
         let this = self.await?;
 
         let config = parse_config_from_source(this.userland_module);
 
-        let mut output_assets = self.edge_files().await?.clone_value();
+        let runtime = self.runtime().await?;
+        let mut output_assets = if &**runtime == "nodejs" {
+            self.node_files().await?.clone_value()
+        } else {
+            self.edge_files().await?.clone_value()
+        };
 
         let node_root = this.project.node_root();
 
@@ -119,14 +177,13 @@ impl MiddlewareEndpoint {
                 .await?
         };
 
-        let matchers = if let Some(matchers) = config.await?.matcher.as_ref() {
+        let config_ref = config.await?;
+
+        let matchers = if let Some(matchers) = config_ref.matcher.as_ref() {
             matchers
                 .iter()
-                .map(|matcher| MiddlewareMatcher {
-                    original_source: matcher.to_string(),
-                    ..Default::default()
-                })
-                .collect()
+                .map(parse_middleware_matcher)
+                .collect::<Result<_>>()?
         } else {
             vec![MiddlewareMatcher {
                 regexp: Some("^/.*$".to_string()),
@@ -135,12 +192,20 @@ impl MiddlewareEndpoint {
             }]
         };
 
+        let regions = config_ref
+            .regions
+            .as_ref()
+            .or(config_ref.preferred_region.as_ref())
+            .map(parse_middleware_regions)
+            .transpose()?;
+
         let edge_function_definition = EdgeFunctionDefinition {
             files: files_paths_from_root,
             name: "middleware".to_string(),
             page: "/".to_string(),
-            regions: None,
+            regions,
             matchers,
+            runtime: (*runtime).to_string(),
             ..Default::default()
         };
         let middleware_manifest_v2 = MiddlewaresManifestV2 {
@@ -181,6 +246,28 @@ impl Endpoint for MiddlewareEndpoint {
                 .await?
                 .clone_value();
 
+            if &**self.runtime().await? == "nodejs" {
+                let node_root_ref = &node_root.await?;
+                let entry_path = self
+                    .node_files()
+                    .await?
+                    .first()
+                    .context("Node.js middleware must have at least one entry chunk")?
+                    .ident()
+                    .path()
+                    .await?;
+                let entry_path = node_root_ref
+                    .get_path_to(&entry_path)
+                    .context("middleware entry path must be inside the node root")?
+                    .to_string();
+
+                return Ok(WrittenEndpoint::NodeJs {
+                    server_entry_path: entry_path,
+                    server_paths,
+                }
+                .cell());
+            }
+
             Ok(WrittenEndpoint::Edge { server_paths }.cell())
         }
         .instrument(span)
@@ -192,8 +279,395 @@ impl Endpoint for MiddlewareEndpoint {
         Ok(self.await?.project.server_changed(self.output_assets()))
     }
 
+    // Middleware has no separate client bundle: `output_assets()` is the
+    // whole of what's emitted for it, edge and Node.js alike. Deriving the
+    // `Completion` from the same turbo-tasks-tracked `output_assets()` that
+    // `server_changed` uses (rather than `Completion::immutable()`) is what
+    // actually drives the dev server's WS-based HMR pipeline for middleware,
+    // since turbo-tasks already recomputes and invalidates it whenever the
+    // source changes —
+    // no separate versioned-content bookkeeping needed.
     #[turbo_tasks::function]
-    fn client_changed(self: Vc<Self>) -> Vc<Completion> {
-        Completion::immutable()
+    async fn client_changed(self: Vc<Self>) -> Result<Vc<Completion>> {
+        Ok(self.await?.project.client_changed(self.output_assets()))
+    }
+}
+
+/// The `type` of a middleware `has`/`missing` request condition.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum MiddlewareMatcherConditionType {
+    Header,
+    Cookie,
+    Query,
+    Host,
+}
+
+/// A single `has`/`missing` request condition. Deserializing this directly
+/// (rather than leaving conditions as untyped JSON) is what makes a
+/// malformed entry, e.g. `{"oops": 1}` or an unknown `type`, fail parsing
+/// instead of silently round-tripping into the manifest.
+///
+/// `key` is optional at the type level because it isn't required for every
+/// condition `type`: `header`/`cookie`/`query` match against a named key,
+/// but `host` matches the whole `value` and is conventionally written as
+/// `{ "type": "host", "value": "example.com" }` with no `key` at all.
+/// [`MiddlewareMatcherCondition::validate`] enforces the per-type shape.
+#[derive(Deserialize, Serialize)]
+struct MiddlewareMatcherCondition {
+    r#type: MiddlewareMatcherConditionType,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+impl MiddlewareMatcherCondition {
+    fn validate(&self) -> Result<()> {
+        match self.r#type {
+            MiddlewareMatcherConditionType::Header
+            | MiddlewareMatcherConditionType::Cookie
+            | MiddlewareMatcherConditionType::Query => {
+                if self.key.is_none() {
+                    bail!(
+                        "middleware matcher conditions of type 'header', 'cookie', or 'query' \
+                         require a 'key'"
+                    );
+                }
+            }
+            MiddlewareMatcherConditionType::Host => {
+                if self.key.is_some() {
+                    bail!("middleware matcher conditions of type 'host' must not specify a 'key'");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The object form of a `config.matcher` entry, as opposed to a bare source
+/// string.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMiddlewareMatcher {
+    source: String,
+    #[serde(default)]
+    has: Vec<MiddlewareMatcherCondition>,
+    #[serde(default)]
+    missing: Vec<MiddlewareMatcherCondition>,
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    locale: Option<bool>,
+}
+
+/// Parses a single `config.matcher` entry, which is either a bare source
+/// string or an object carrying `source` plus the richer `has`/`missing`
+/// request conditions, `methods`, and `locale`.
+fn parse_middleware_matcher(matcher: &serde_json::Value) -> Result<MiddlewareMatcher> {
+    let ObjectMiddlewareMatcher {
+        source,
+        has,
+        missing,
+        methods,
+        locale,
+    } = match matcher {
+        serde_json::Value::String(source) => ObjectMiddlewareMatcher {
+            source: source.clone(),
+            has: Vec::new(),
+            missing: Vec::new(),
+            methods: Vec::new(),
+            locale: None,
+        },
+        serde_json::Value::Object(_) => serde_json::from_value(matcher.clone())
+            .context("invalid middleware matcher")?,
+        _ => bail!("middleware matcher must be a string or an object, found {matcher}"),
+    };
+
+    Ok(MiddlewareMatcher {
+        regexp: Some(middleware_source_to_regexp(&source)),
+        original_source: source,
+        has: middleware_matcher_conditions(has)?,
+        missing: middleware_matcher_conditions(missing)?,
+        methods: (!methods.is_empty()).then_some(methods),
+        locale,
+        ..Default::default()
+    })
+}
+
+/// Converts parsed `has`/`missing` conditions back into the JSON shape
+/// `MiddlewareMatcher` expects, or `None` when there are none.
+fn middleware_matcher_conditions(
+    conditions: Vec<MiddlewareMatcherCondition>,
+) -> Result<Option<Vec<serde_json::Value>>> {
+    if conditions.is_empty() {
+        return Ok(None);
+    }
+
+    conditions
+        .into_iter()
+        .map(|condition| {
+            condition.validate()?;
+            Ok(serde_json::to_value(condition)?)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// A minimal `path-to-regexp`-style conversion from a matcher `source` into
+/// the regex Next.js tests incoming request paths against.
+///
+/// Named params (`:slug`), optional segments (`:slug?`), and catch-alls
+/// (`:slug*`/`:slug+`) round-trip through capture groups, exactly like
+/// `path-to-regexp`. Parenthesized groups are `path-to-regexp`'s escape
+/// hatch for a custom regex fragment rather than part of the literal path,
+/// so they're copied through untouched instead of being escaped: the single
+/// most common matcher in the wild, the negated-lookahead
+/// `'/((?!api|_next/static|_next/image|favicon.ico).*)'`, is already a
+/// regex body, not literal text to match verbatim. Everything else is
+/// escaped as literal path text.
+fn middleware_source_to_regexp(source: &str) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let mut regexp = String::from("^");
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '(' => {
+                let start = i;
+                let mut depth = 1;
+                i += 1;
+                while i < chars.len() && depth > 0 {
+                    match chars[i] {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                regexp.extend(&chars[start..i]);
+            }
+            ':' => {
+                let mut j = i + 1;
+                while j < chars.len() && (chars[j].is_alphanumeric() || chars[j] == '_') {
+                    j += 1;
+                }
+                match chars.get(j) {
+                    Some('*') => {
+                        // Catch-all: fold the slash we already emitted for
+                        // this segment into the optional group, so
+                        // `/foo/:path*` also matches `/foo`.
+                        if regexp.ends_with('/') {
+                            regexp.pop();
+                        }
+                        regexp.push_str("(?:/(.+?))?");
+                        j += 1;
+                    }
+                    Some('+') => {
+                        regexp.push_str("(.+?)");
+                        j += 1;
+                    }
+                    Some('?') => {
+                        if regexp.ends_with('/') {
+                            regexp.pop();
+                        }
+                        regexp.push_str("(?:/([^/]+?))?");
+                        j += 1;
+                    }
+                    _ => {
+                        regexp.push_str("([^/]+?)");
+                    }
+                }
+                i = j;
+            }
+            ch => {
+                regexp.push_str(&escape_regexp_literal(ch));
+                i += 1;
+            }
+        }
+    }
+    regexp.push_str("(?:/)?$");
+    regexp
+}
+
+fn escape_regexp_literal(ch: char) -> String {
+    if matches!(
+        ch,
+        '.' | '+' | '*' | '?' | '^' | '$' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '\\'
+    ) {
+        format!("\\{ch}")
+    } else {
+        ch.to_string()
+    }
+}
+
+/// The Vercel edge regions middleware can be pinned to via `regions` /
+/// `preferredRegion`, mirroring the webpack build's validation.
+const VALID_MIDDLEWARE_REGIONS: &[&str] = &[
+    "arn1", "bom1", "bru1", "cdg1", "cle1", "cpt1", "dub1", "fra1", "gru1", "hkg1", "hnd1", "iad1",
+    "icn1", "kix1", "lhr1", "pdx1", "sfo1", "sin1", "syd1",
+];
+
+/// Parses the `regions`/`preferredRegion` field of a middleware's exported
+/// `config`, accepting either a single region string or an array of them.
+fn parse_middleware_regions(regions: &serde_json::Value) -> Result<Vec<String>> {
+    let regions = match regions {
+        serde_json::Value::String(region) => vec![region.clone()],
+        serde_json::Value::Array(regions) => regions
+            .iter()
+            .map(|region| {
+                region
+                    .as_str()
+                    .map(str::to_string)
+                    .context("middleware regions must be strings")
+            })
+            .collect::<Result<_>>()?,
+        _ => bail!("middleware regions must be a string or an array of strings, found {regions}"),
+    };
+
+    for region in &regions {
+        if !VALID_MIDDLEWARE_REGIONS.contains(&region.as_str()) {
+            bail!(
+                "Invalid region '{region}' for middleware. Valid regions are: \
+                 {VALID_MIDDLEWARE_REGIONS:?}",
+            );
+        }
+    }
+
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use next_core::mode::NextMode;
+    use turbo_tasks_testing::VcStorage;
+
+    use super::*;
+
+    #[test]
+    fn named_param_matcher_compiles_to_capture_groups() {
+        let matcher = parse_middleware_matcher(&serde_json::json!("/about/:path*")).unwrap();
+        assert_eq!(
+            matcher.regexp.as_deref(),
+            Some("^/about(?:/(.+?))?(?:/)?$")
+        );
+    }
+
+    #[test]
+    fn parses_single_region_string() {
+        assert_eq!(
+            parse_middleware_regions(&serde_json::json!("iad1")).unwrap(),
+            vec!["iad1".to_string()]
+        );
+    }
+
+    #[test]
+    fn parses_region_array() {
+        assert_eq!(
+            parse_middleware_regions(&serde_json::json!(["iad1", "sfo1"])).unwrap(),
+            vec!["iad1".to_string(), "sfo1".to_string()]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_region() {
+        let err = parse_middleware_regions(&serde_json::json!("mars1")).unwrap_err();
+        assert!(err.to_string().contains("Invalid region"));
+    }
+
+    #[test]
+    fn object_matcher_with_has_and_locale_round_trips() {
+        let matcher = parse_middleware_matcher(&serde_json::json!({
+            "source": "/dashboard",
+            "has": [{ "type": "header", "key": "x-team", "value": "admin" }],
+            "methods": ["GET"],
+            "locale": false,
+        }))
+        .unwrap();
+
+        assert_eq!(matcher.locale, Some(false));
+        assert_eq!(matcher.methods, Some(vec!["GET".to_string()]));
+        assert!(matcher.has.is_some());
+    }
+
+    #[test]
+    fn host_condition_without_key_is_accepted() {
+        let matcher = parse_middleware_matcher(&serde_json::json!({
+            "source": "/",
+            "has": [{ "type": "host", "value": "example.com" }],
+        }))
+        .unwrap();
+
+        assert!(matcher.has.is_some());
+    }
+
+    #[test]
+    fn host_condition_with_key_is_rejected() {
+        let err = parse_middleware_matcher(&serde_json::json!({
+            "source": "/",
+            "has": [{ "type": "host", "key": "oops", "value": "example.com" }],
+        }))
+        .unwrap_err();
+
+        assert!(err.to_string().contains("host"));
+    }
+
+    #[test]
+    fn malformed_condition_is_rejected() {
+        let err = parse_middleware_matcher(&serde_json::json!({
+            "source": "/",
+            "has": [{ "oops": 1 }],
+        }))
+        .unwrap_err();
+
+        assert!(err.to_string().contains("invalid middleware matcher"));
+    }
+
+    /// The negated-lookahead matcher Next.js's own `create-next-app`
+    /// templates ship by default. The `regexp` must stay a working regex
+    /// instead of having its parens/pipes/dots escaped into literal text,
+    /// or middleware silently stops running on every request.
+    #[test]
+    fn raw_regex_group_matcher_is_preserved() {
+        let source = "/((?!api|_next/static|_next/image|favicon.ico).*)";
+        let matcher = parse_middleware_matcher(&serde_json::json!(source)).unwrap();
+        assert_eq!(
+            matcher.regexp.as_deref(),
+            Some("^/((?!api|_next/static|_next/image|favicon.ico).*)(?:/)?$")
+        );
+    }
+
+    /// The edge middleware bundle can't be patched incrementally, so a dev
+    /// build includes the HMR/refresh runtime entries while a production
+    /// build doesn't. Goes through `MiddlewareEndpoint::edge_files()` itself
+    /// (rather than calling `get_server_runtime_entries` directly) so this
+    /// actually guards against `edge_files()` going back to hardcoding
+    /// `NextMode::Development` instead of reading `self.project.next_mode()`.
+    #[tokio::test]
+    async fn edge_files_differ_between_dev_and_build() -> Result<()> {
+        VcStorage::with(async {
+            // `Project::new_test` and the `test_*` helpers below are
+            // lightweight fixtures (no real filesystem/config) kept on
+            // `Project` specifically for crate-local unit tests like this
+            // one.
+            async fn edge_file_count(mode: NextMode) -> Result<usize> {
+                let project = Project::new_test(mode);
+                let context = project.test_context();
+                let userland_module =
+                    project.test_ecmascript_module("export default function middleware() {}");
+                let endpoint = MiddlewareEndpoint::new(project, context, userland_module);
+                Ok(endpoint.edge_files().await?.await?.len())
+            }
+
+            let dev_file_count = edge_file_count(NextMode::Development).await?;
+            let build_file_count = edge_file_count(NextMode::Build).await?;
+
+            assert_ne!(
+                dev_file_count, build_file_count,
+                "a production build must not ship the dev-only HMR/refresh runtime chunks"
+            );
+
+            Ok(())
+        })
+        .await
     }
 }